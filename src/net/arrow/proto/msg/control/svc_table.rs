@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::fmt;
 use std::mem;
 
+use std::error::Error;
+use std::collections::VecDeque;
 use std::iter::FromIterator;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
@@ -34,8 +38,112 @@ pub const SVC_TYPE_UNSUPPORTED_RTSP: u16 = 0x0004;
 pub const SVC_TYPE_HTTP:             u16 = 0x0005;
 pub const SVC_TYPE_MJPEG:            u16 = 0x0006;
 pub const SVC_TYPE_LOCKED_MJPEG:     u16 = 0x0007;
+pub const SVC_TYPE_ONION_RTSP:       u16 = 0x0008;
+pub const SVC_TYPE_ONION_TCP:        u16 = 0x0009;
+pub const SVC_TYPE_REVERSE_TCP:      u16 = 0x000a;
 pub const SVC_TYPE_TCP:              u16 = 0xffff;
 
+/// Service table protocol version using only the legacy fixed-size
+/// `ServiceHeader` + path layout (no host name or proxy blocks). This is
+/// the version every peer is expected to understand.
+pub const SVC_TABLE_VERSION_1: u16 = 1;
+
+/// Service table protocol version adding the host name block (hostnames
+/// and Tor onion addresses) and the proxy block to every `Service` record.
+pub const SVC_TABLE_VERSION_2: u16 = 2;
+
+/// Highest service table protocol version this build can produce.
+pub const CURRENT_SVC_TABLE_VERSION: u16 = SVC_TABLE_VERSION_2;
+
+/// Capability bit indicating support for host name addressing (DNS
+/// hostnames and Tor v3 onion addresses) added in version 2.
+pub const CAP_HOST_ADDRESSING: u32 = 0x0000_0001;
+
+/// Capability bit indicating support for the `proxy` (SOCKS5) attribute
+/// added in version 2.
+pub const CAP_PROXY: u32 = 0x0000_0002;
+
+/// Capabilities supported by this build.
+pub const CURRENT_SVC_TABLE_CAPABILITIES: u32 = CAP_HOST_ADDRESSING | CAP_PROXY;
+
+/// Sentinel value for `ServiceHeader::ip_version` indicating that the
+/// service is addressed by name (a DNS hostname or a Tor v3 onion address)
+/// rather than by a literal IP address. When this sentinel is used, the
+/// `ip_addr` and `port` header fields are zeroed and the actual host name
+/// and port are carried in a length-prefixed block right after the header.
+const IP_VERSION_HOST: u8 = 0xff;
+
+/// Length (in base32 characters) of the public key + checksum + version
+/// portion of a Tor v3 onion address, i.e. the label before the `.onion`
+/// suffix.
+const ONION_V3_LABEL_LEN: usize = 56;
+
+/// Suffix expected on every onion address.
+const ONION_SUFFIX: &'static str = ".onion";
+
+/// Check that a given host is a syntactically valid Tor v3 onion address,
+/// i.e. a 56-character base32 label (encoding the ed25519 public key, the
+/// sha3 checksum and the version byte) followed by the `.onion` suffix.
+fn is_valid_onion_v3_address(host: &str) -> bool {
+    if !host.ends_with(ONION_SUFFIX) {
+        return false
+    }
+
+    let label = &host[..host.len() - ONION_SUFFIX.len()];
+
+    label.len() == ONION_V3_LABEL_LEN
+        && label.bytes().all(|b| {
+            (b >= b'a' && b <= b'z') || (b >= b'2' && b <= b'7')
+        })
+}
+
+/// Error returned when a given host name is not a valid Tor v3 onion
+/// address.
+#[derive(Debug, Clone)]
+pub struct InvalidHostError {
+    host: String,
+}
+
+impl fmt::Display for InvalidHostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\" is not a valid onion (v3) address", self.host)
+    }
+}
+
+impl Error for InvalidHostError {
+    fn description(&self) -> &str {
+        "invalid onion address"
+    }
+}
+
+/// A service address given by name rather than by a literal IP address,
+/// e.g. a DNS hostname or a Tor v3 onion address.
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct HostAddress {
+    host: String,
+    port: u16,
+}
+
+impl HostAddress {
+    /// Create a new host address.
+    fn new(host: String, port: u16) -> HostAddress {
+        HostAddress {
+            host: host,
+            port: port,
+        }
+    }
+
+    /// Get the host name.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Get the port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
 /// Service type.
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub enum ServiceType {
@@ -55,6 +163,15 @@ pub enum ServiceType {
     MJPEG,
     /// Remote MJPEG service requiring authentication.
     LockedMJPEG,
+    /// Remote RTSP service reachable only by a Tor v3 onion address.
+    OnionRTSP,
+    /// General purpose TCP service reachable only by a Tor v3 onion
+    /// address.
+    OnionTCP,
+    /// General purpose TCP service behind a NAT that cannot be dialed
+    /// directly; the data plane is established by the agent dialing out
+    /// and multiplexing a reverse tunnel identified by a token.
+    ReverseTCP,
     /// General purpose TCP service.
     TCP,
 }
@@ -71,9 +188,24 @@ impl ServiceType {
             &ServiceType::HTTP            => SVC_TYPE_HTTP,
             &ServiceType::MJPEG           => SVC_TYPE_MJPEG,
             &ServiceType::LockedMJPEG     => SVC_TYPE_LOCKED_MJPEG,
+            &ServiceType::OnionRTSP       => SVC_TYPE_ONION_RTSP,
+            &ServiceType::OnionTCP        => SVC_TYPE_ONION_TCP,
+            &ServiceType::ReverseTCP      => SVC_TYPE_REVERSE_TCP,
             &ServiceType::TCP             => SVC_TYPE_TCP,
         }
     }
+
+    /// Check if this service type was introduced in `SVC_TABLE_VERSION_2`
+    /// and therefore cannot be represented in a `SVC_TABLE_VERSION_1`
+    /// header.
+    pub fn is_v2_only(&self) -> bool {
+        match self {
+            &ServiceType::OnionRTSP
+            | &ServiceType::OnionTCP
+            | &ServiceType::ReverseTCP => true,
+            _ => false,
+        }
+    }
 }
 
 /// Service Table item header.
@@ -87,8 +219,15 @@ struct ServiceHeader {
     port:       u16,
 }
 
-impl<'a> From<&'a Service> for ServiceHeader {
-    fn from(service: &'a Service) -> ServiceHeader {
+impl ServiceHeader {
+    /// Build a service header for a given (negotiated) service table
+    /// protocol version. A `SVC_TABLE_VERSION_1` peer understands neither
+    /// the `IP_VERSION_HOST` sentinel nor any `svc_type` introduced after
+    /// it, so a host-addressed service or a `ServiceType::is_v2_only`
+    /// service degrades to a zeroed, legacy-valid header carrying
+    /// `SVC_TYPE_UNSUPPORTED_RTSP` instead of a header the peer cannot
+    /// safely parse.
+    fn for_version(service: &Service, version: u16) -> ServiceHeader {
         let service_type = service.service_type();
 
         let null_maddress = MacAddr::new(0, 0, 0, 0, 0, 0);
@@ -98,6 +237,35 @@ impl<'a> From<&'a Service> for ServiceHeader {
 
         let maddress = service.mac()
             .unwrap_or(&null_maddress);
+
+        if version < SVC_TABLE_VERSION_2
+            && (service.host().is_some() || service_type.is_v2_only()) {
+            let iaddress = null_saddress.ip();
+
+            return ServiceHeader {
+                svc_id:     service.id(),
+                svc_type:   SVC_TYPE_UNSUPPORTED_RTSP,
+                mac_addr:   maddress.octets(),
+                ip_version: iaddress.version(),
+                ip_addr:    iaddress.bytes(),
+                port:       0,
+            }
+        }
+
+        if service.host().is_some() {
+            // the service is addressed by name; the IP/port fields are
+            // zeroed and the host name is encoded separately (see
+            // Service::encode)
+            return ServiceHeader {
+                svc_id:     service.id(),
+                svc_type:   service_type.code(),
+                mac_addr:   maddress.octets(),
+                ip_version: IP_VERSION_HOST,
+                ip_addr:    [0; 16],
+                port:       0,
+            }
+        }
+
         let saddress = service.address()
             .unwrap_or(&null_saddress);
         let iaddress = saddress.ip();
@@ -135,6 +303,8 @@ pub struct Service {
     id:       u16,
     mac:      Option<MacAddr>,
     address:  Option<SocketAddr>,
+    host:     Option<HostAddress>,
+    proxy:    Option<SocketAddr>,
     path:     Option<String>,
 }
 
@@ -146,6 +316,8 @@ impl Service {
             id:       id,
             mac:      svc.mac,
             address:  svc.address,
+            host:     svc.host,
+            proxy:    svc.proxy,
             path:     svc.path,
         }
     }
@@ -157,6 +329,8 @@ impl Service {
             id:       0,
             mac:      None,
             address:  None,
+            host:     None,
+            proxy:    None,
             path:     None,
         }
     }
@@ -168,6 +342,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     Some(path),
         }
     }
@@ -179,6 +355,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     path,
         }
     }
@@ -190,6 +368,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     None,
         }
     }
@@ -201,6 +381,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     Some(path),
         }
     }
@@ -212,6 +394,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     None,
         }
     }
@@ -223,6 +407,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     Some(path),
         }
     }
@@ -234,6 +420,8 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
             path:     path,
         }
     }
@@ -245,10 +433,85 @@ impl Service {
             id:       id,
             mac:      Some(mac),
             address:  Some(address),
+            host:     None,
+            proxy:    None,
+            path:     None,
+        }
+    }
+
+    /// Create a new RTSP service reachable only through a Tor v3 onion
+    /// address. The `host` must be a valid `<56-char-base32>.onion` label.
+    pub fn onion_rtsp(
+        id: u16,
+        mac: MacAddr,
+        host: String,
+        port: u16,
+        path: Option<String>) -> Result<Service, InvalidHostError> {
+        if !is_valid_onion_v3_address(&host) {
+            return Err(InvalidHostError { host: host })
+        }
+
+        Ok(Service {
+            svc_type: ServiceType::OnionRTSP,
+            id:       id,
+            mac:      Some(mac),
+            address:  None,
+            host:     Some(HostAddress::new(host, port)),
+            proxy:    None,
+            path:     path,
+        })
+    }
+
+    /// Create a new general purpose TCP service reachable only through a
+    /// Tor v3 onion address. The `host` must be a valid
+    /// `<56-char-base32>.onion` label.
+    pub fn onion_tcp(
+        id: u16,
+        mac: MacAddr,
+        host: String,
+        port: u16) -> Result<Service, InvalidHostError> {
+        if !is_valid_onion_v3_address(&host) {
+            return Err(InvalidHostError { host: host })
+        }
+
+        Ok(Service {
+            svc_type: ServiceType::OnionTCP,
+            id:       id,
+            mac:      Some(mac),
+            address:  None,
+            host:     Some(HostAddress::new(host, port)),
+            proxy:    None,
             path:     None,
+        })
+    }
+
+    /// Create a new reverse-tunnel TCP service for a device that cannot be
+    /// dialed directly (e.g. behind NAT). `bind_addr` is the local address
+    /// of the device the agent connects each tunneled connection to, and `token` is
+    /// the tunnel identifier the agent and the server use to match up the
+    /// outbound control channel with this service's data plane; it is
+    /// carried in the same trailing bytes a path would normally occupy.
+    pub fn reverse_tcp(id: u16, mac: MacAddr, bind_addr: SocketAddr, token: String) -> Service {
+        Service {
+            svc_type: ServiceType::ReverseTCP,
+            id:       id,
+            mac:      Some(mac),
+            address:  Some(bind_addr),
+            host:     None,
+            proxy:    None,
+            path:     Some(token),
         }
     }
 
+    /// Wrap this service so that it is dialed through a given SOCKS5 proxy
+    /// rather than connected to directly. This is useful for routing
+    /// specific services through a local or remote Tor/SOCKS proxy while
+    /// leaving other services direct.
+    pub fn via_proxy(mut self, proxy: SocketAddr) -> Service {
+        self.proxy = Some(proxy);
+        self
+    }
+
     /// Check if this is the Control Protocol service.
     pub fn is_control(&self) -> bool {
         self.svc_type == ServiceType::ControlProtocol
@@ -274,6 +537,17 @@ impl Service {
         self.address.as_ref()
     }
 
+    /// Get service host name and port (for services addressed by name,
+    /// e.g. a DNS hostname or a Tor v3 onion address).
+    pub fn host(&self) -> Option<&HostAddress> {
+        self.host.as_ref()
+    }
+
+    /// Get the SOCKS5 proxy this service must be dialed through, if any.
+    pub fn proxy(&self) -> Option<&SocketAddr> {
+        self.proxy.as_ref()
+    }
+
     /// Get service path.
     pub fn path(&self) -> Option<&str> {
         self.path.as_ref()
@@ -281,27 +555,84 @@ impl Service {
     }
 }
 
-impl Encode for Service {
-    fn encode(&self, buf: &mut BytesMut) {
-        ServiceHeader::from(self)
+impl Service {
+    /// Encode this service using a given (negotiated) service table
+    /// protocol version. Peers on `SVC_TABLE_VERSION_1` only ever see the
+    /// legacy fixed-size header + path layout; the host name and proxy
+    /// blocks (added in `SVC_TABLE_VERSION_2`) are simply omitted, so a
+    /// host-only service degrades to a header with a zeroed address.
+    pub fn encode_versioned(&self, version: u16, buf: &mut BytesMut) {
+        ServiceHeader::for_version(self, version)
             .encode(buf);
 
+        if version < SVC_TABLE_VERSION_2 {
+            let path = self.path()
+                .unwrap_or("");
+
+            buf.extend(path.as_bytes());
+            buf.extend(&[0]);
+
+            return
+        }
+
+        // a host name (DNS or onion address) is encoded as a length-
+        // prefixed block (host bytes + port) right after the header,
+        // ahead of the trailing path bytes
+        if let Some(host) = self.host.as_ref() {
+            let host_bytes = host.host().as_bytes();
+
+            let port_be = host.port().to_be();
+
+            buf.extend(&[host_bytes.len() as u8]);
+            buf.extend(host_bytes);
+            buf.extend(utils::as_bytes(&port_be));
+        }
+
+        // the SOCKS5 proxy endpoint (if any) is encoded as a presence
+        // flag followed by an IP version byte, 16 raw address bytes and a
+        // port, right after the host block and ahead of the path bytes
+        if let Some(proxy) = self.proxy.as_ref() {
+            let iaddress = proxy.ip();
+            let port_be = proxy.port().to_be();
+
+            buf.extend(&[1]);
+            buf.extend(&[iaddress.version()]);
+            buf.extend(&iaddress.bytes());
+            buf.extend(utils::as_bytes(&port_be));
+        } else {
+            buf.extend(&[0]);
+        }
+
         let path = self.path()
             .unwrap_or("");
 
         buf.extend(path.as_bytes());
         buf.extend(&[0]);
     }
-}
 
-impl MessageBody for Service {
-    fn len(&self) -> usize {
+    /// Get the encoded length of this service for a given (negotiated)
+    /// service table protocol version.
+    pub fn len_versioned(&self, version: u16) -> usize {
         let plen = self.path()
             .unwrap_or("")
             .as_bytes()
             .len() + 1;
 
-        mem::size_of::<ServiceHeader>() + plen
+        if version < SVC_TABLE_VERSION_2 {
+            return mem::size_of::<ServiceHeader>() + plen
+        }
+
+        let hlen = self.host.as_ref()
+            .map(|host| 1 + host.host().as_bytes().len() + 2)
+            .unwrap_or(0);
+
+        let xlen = if self.proxy.is_some() {
+            1 + 1 + 16 + 2
+        } else {
+            1
+        };
+
+        mem::size_of::<ServiceHeader>() + hlen + xlen + plen
     }
 }
 
@@ -310,6 +641,31 @@ pub trait ServiceTable {
     /// Get service with a given ID.
     fn get(&self, id: u16) -> Option<Service>;
 
+    /// Get the lowest service table protocol version this table can be
+    /// encoded as without degrading any of its services (i.e. how far it
+    /// can be downgraded for an older peer without losing information).
+    fn min_version(&self) -> u16;
+
+    /// Get the set of optional features (see the `CAP_*` constants) this
+    /// table can make use of when encoded at the version it is currently
+    /// set to.
+    fn capabilities(&self) -> u32;
+
+    /// Get the current generation of this table. This is bumped every
+    /// time the table records a change that `diff` can report (see
+    /// `SimpleServiceTable::update_services`).
+    fn generation(&self) -> u64;
+
+    /// Get the oldest generation this table can still compute a complete
+    /// diff for. A caller whose last known generation is older than this
+    /// must be sent a full table encode instead of a `diff`.
+    fn oldest_generation(&self) -> u64;
+
+    /// Compute the set of services added, removed or changed since a
+    /// given generation. The result is only complete when `since` is not
+    /// older than `oldest_generation()`.
+    fn diff(&self, since: u64) -> ServiceTableDelta;
+
     /// Convert this service table into a trait object.
     fn boxed(self) -> BoxServiceTable;
 }
@@ -323,21 +679,214 @@ impl ServiceTable for Box<ServiceTable> {
             .get(id)
     }
 
+    fn min_version(&self) -> u16 {
+        self.as_ref()
+            .min_version()
+    }
+
+    fn capabilities(&self) -> u32 {
+        self.as_ref()
+            .capabilities()
+    }
+
+    fn generation(&self) -> u64 {
+        self.as_ref()
+            .generation()
+    }
+
+    fn oldest_generation(&self) -> u64 {
+        self.as_ref()
+            .oldest_generation()
+    }
+
+    fn diff(&self, since: u64) -> ServiceTableDelta {
+        self.as_ref()
+            .diff(since)
+    }
+
     fn boxed(self) -> BoxServiceTable {
         self
     }
 }
 
+/// Incremental service table update carrying only the services that were
+/// added, removed or changed since a given generation, rather than the
+/// full table. Produced by `ServiceTable::diff`.
+///
+/// A peer that is syncing for the first time, or whose last known
+/// generation is older than `ServiceTable::oldest_generation`, must be
+/// sent a full table encode instead, since the history needed to compute
+/// a delta for it is no longer retained.
+pub struct ServiceTableDelta {
+    version:     u16,
+    generation:  u64,
+    removed_ids: Vec<u16>,
+    upserts:     Vec<Service>,
+}
+
+impl ServiceTableDelta {
+    /// Get the generation this delta brings a peer up to.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get IDs of services removed since the base generation.
+    pub fn removed_ids(&self) -> &[u16] {
+        &self.removed_ids
+    }
+
+    /// Get services added or changed since the base generation.
+    pub fn upserts(&self) -> &[Service] {
+        &self.upserts
+    }
+}
+
+impl Encode for ServiceTableDelta {
+    fn encode(&self, buf: &mut BytesMut) {
+        let generation_be = self.generation.to_be();
+        let removed_count_be = (self.removed_ids.len() as u16).to_be();
+        let upsert_count_be = (self.upserts.len() as u16).to_be();
+
+        buf.extend(utils::as_bytes(&generation_be));
+        buf.extend(utils::as_bytes(&removed_count_be));
+
+        for id in &self.removed_ids {
+            let id_be = id.to_be();
+
+            buf.extend(utils::as_bytes(&id_be));
+        }
+
+        buf.extend(utils::as_bytes(&upsert_count_be));
+
+        for svc in &self.upserts {
+            svc.encode_versioned(self.version, buf);
+        }
+    }
+}
+
+impl MessageBody for ServiceTableDelta {
+    fn len(&self) -> usize {
+        let mut len = mem::size_of::<u64>()
+            + 2 * mem::size_of::<u16>()
+            + self.removed_ids.len() * mem::size_of::<u16>();
+
+        for svc in &self.upserts {
+            len += svc.len_versioned(self.version);
+        }
+
+        len
+    }
+}
+
+/// Maximum number of past generations a `SimpleServiceTable` retains
+/// change history for before a lagging peer must be sent a full resync
+/// instead of a diff.
+const MAX_RETAINED_GENERATIONS: u64 = 64;
+
+/// A single recorded change to a `SimpleServiceTable`, tagged with the
+/// generation it happened at.
+#[derive(Clone)]
+enum ServiceChange {
+    /// A service was added, or an existing one (matched by ID) changed.
+    Upsert(Service),
+    /// The service with a given ID was removed.
+    Removed(u16),
+}
+
 /// Simple service table implementation.
 pub struct SimpleServiceTable {
-    services: Vec<Service>,
+    services:   Vec<Service>,
+    version:    u16,
+    generation: u64,
+    history:    VecDeque<(u64, ServiceChange)>,
 }
 
 impl<I> From<I> for SimpleServiceTable
     where I: IntoIterator<Item=Service> {
     fn from(services: I) -> SimpleServiceTable {
         SimpleServiceTable {
-            services: Vec::from_iter(services),
+            services:   Vec::from_iter(services),
+            version:    CURRENT_SVC_TABLE_VERSION,
+            generation: 0,
+            history:    VecDeque::new(),
+        }
+    }
+}
+
+impl SimpleServiceTable {
+    /// Encode this table at a given service table protocol version rather
+    /// than at `CURRENT_SVC_TABLE_VERSION`. The version is expected to
+    /// already be the result of `negotiate_version` (or some other agreed
+    /// upon value); this method does not itself validate or clamp it.
+    pub fn with_version(mut self, version: u16) -> SimpleServiceTable {
+        self.version = version;
+        self
+    }
+
+    /// Compute the service table protocol version to use with a peer given
+    /// the highest version it advertised as supporting, and set this table
+    /// up to be encoded at that (possibly downgraded) version.
+    ///
+    /// The agreed version is the lower of `CURRENT_SVC_TABLE_VERSION` and
+    /// the peer's advertised version, clamped to never go below
+    /// `SVC_TABLE_VERSION_1` (the baseline every peer must understand).
+    /// Once downgraded, `capabilities()` and `encode()` only ever report
+    /// and emit what that agreed version actually supports.
+    pub fn negotiate_version(self, peer_version: u16) -> SimpleServiceTable {
+        let agreed = cmp::min(CURRENT_SVC_TABLE_VERSION, peer_version)
+            .max(SVC_TABLE_VERSION_1);
+
+        self.with_version(agreed)
+    }
+
+    /// Get the service table protocol version this table is currently set
+    /// to be encoded as.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Replace the current services with a new set (e.g. the result of a
+    /// fresh network scan), recording the incremental change set (added,
+    /// removed and changed services, matched by ID) and bumping the
+    /// generation counter if anything actually changed.
+    ///
+    /// History is retained for up to `MAX_RETAINED_GENERATIONS`
+    /// generations; once a change falls out of that window it can no
+    /// longer be reported by `diff` (see `oldest_generation`).
+    pub fn update_services<I>(&mut self, services: I)
+        where I: IntoIterator<Item=Service> {
+        let new_services = Vec::from_iter(services);
+
+        let mut changes = Vec::new();
+
+        for old in &self.services {
+            if !new_services.iter().any(|svc| svc.id() == old.id()) {
+                changes.push(ServiceChange::Removed(old.id()));
+            }
+        }
+
+        for new in &new_services {
+            if !self.services.contains(new) {
+                changes.push(ServiceChange::Upsert(new.clone()));
+            }
+        }
+
+        self.services = new_services;
+
+        if changes.is_empty() {
+            return
+        }
+
+        self.generation += 1;
+
+        for change in changes {
+            self.history.push_back((self.generation, change));
+        }
+
+        let cutoff = self.generation.saturating_sub(MAX_RETAINED_GENERATIONS);
+
+        while self.history.front().map(|&(generation, _)| generation <= cutoff).unwrap_or(false) {
+            self.history.pop_front();
         }
     }
 }
@@ -357,6 +906,69 @@ impl ServiceTable for SimpleServiceTable {
         None
     }
 
+    fn min_version(&self) -> u16 {
+        let needs_v2 = self.services.iter()
+            .any(|svc| {
+                svc.host().is_some()
+                    || svc.proxy().is_some()
+                    || svc.service_type().is_v2_only()
+            });
+
+        if needs_v2 {
+            SVC_TABLE_VERSION_2
+        } else {
+            SVC_TABLE_VERSION_1
+        }
+    }
+
+    fn capabilities(&self) -> u32 {
+        if self.version < SVC_TABLE_VERSION_2 {
+            CURRENT_SVC_TABLE_CAPABILITIES & !(CAP_HOST_ADDRESSING | CAP_PROXY)
+        } else {
+            CURRENT_SVC_TABLE_CAPABILITIES
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn oldest_generation(&self) -> u64 {
+        self.history.front()
+            .map(|&(generation, _)| generation - 1)
+            .unwrap_or(self.generation)
+    }
+
+    fn diff(&self, since: u64) -> ServiceTableDelta {
+        let mut removed_ids = Vec::new();
+        let mut upserts = Vec::<Service>::new();
+
+        for &(generation, ref change) in &self.history {
+            if generation <= since {
+                continue
+            }
+
+            match change {
+                &ServiceChange::Removed(id) => {
+                    upserts.retain(|svc| svc.id() != id);
+                    removed_ids.push(id);
+                }
+                &ServiceChange::Upsert(ref svc) => {
+                    removed_ids.retain(|&id| id != svc.id());
+                    upserts.retain(|s| s.id() != svc.id());
+                    upserts.push(svc.clone());
+                }
+            }
+        }
+
+        ServiceTableDelta {
+            version:     self.version,
+            generation:  self.generation,
+            removed_ids: removed_ids,
+            upserts:     upserts,
+        }
+    }
+
     fn boxed(self) -> BoxServiceTable {
         Box::new(self)
     }
@@ -364,25 +976,150 @@ impl ServiceTable for SimpleServiceTable {
 
 impl Encode for SimpleServiceTable {
     fn encode(&self, buf: &mut BytesMut) {
+        let version_be = self.version.to_be();
+        let capabilities_be = self.capabilities().to_be();
+
+        buf.extend(utils::as_bytes(&version_be));
+        buf.extend(utils::as_bytes(&capabilities_be));
+
         for svc in &self.services {
-            svc.encode(buf);
+            svc.encode_versioned(self.version, buf);
         }
 
         Service::control()
-            .encode(buf)
+            .encode_versioned(self.version, buf)
     }
 }
 
 impl MessageBody for SimpleServiceTable {
     fn len(&self) -> usize {
-        let mut len = 0;
+        // version (u16) + capabilities (u32)
+        let mut len = mem::size_of::<u16>() + mem::size_of::<u32>();
 
         for svc in &self.services {
-            len += svc.len();
+            len += svc.len_versioned(self.version);
         }
 
         let control = Service::control();
 
-        len + control.len()
+        len + control.len_versioned(self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Byte length of an encoded `ServiceHeader`: svc_id (2) + svc_type (2)
+    /// + mac_addr (6) + ip_version (1) + ip_addr (16) + port (2).
+    const HEADER_LEN: usize = 29;
+
+    /// Pull the fields of a `ServiceHeader` back out of its encoded,
+    /// big-endian on-the-wire bytes. There is no `Decode` counterpart for
+    /// `ServiceHeader` in this tree yet; this only extracts what the
+    /// round-trip tests below need to check.
+    fn parse_header(buf: &[u8]) -> (u16, u16, u8, u16) {
+        let svc_id = ((buf[0] as u16) << 8) | buf[1] as u16;
+        let svc_type = ((buf[2] as u16) << 8) | buf[3] as u16;
+        let ip_version = buf[10];
+        let port = ((buf[27] as u16) << 8) | buf[28] as u16;
+
+        (svc_id, svc_type, ip_version, port)
+    }
+
+    #[test]
+    fn v1_peer_decodes_common_subset_of_v2_table() {
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let tcp_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let plain = Service::tcp(1, mac, tcp_addr);
+        let onion = Service::onion_tcp(
+            2, mac, format!("{}{}", "a".repeat(ONION_V3_LABEL_LEN), ONION_SUFFIX), 9000)
+            .unwrap();
+
+        let table = SimpleServiceTable::from(vec![plain.clone(), onion.clone()]);
+
+        // the table is only safe to downgrade to v1 by omitting (or
+        // degrading) the onion service, so min_version must say so
+        assert_eq!(table.min_version(), SVC_TABLE_VERSION_2);
+
+        // a service a v1 peer already understands round-trips unchanged
+        // even when encoded at SVC_TABLE_VERSION_1
+        let mut buf = BytesMut::new();
+
+        plain.encode_versioned(SVC_TABLE_VERSION_1, &mut buf);
+
+        let (svc_id, svc_type, ip_version, port) = parse_header(&buf[..HEADER_LEN]);
+
+        assert_eq!(svc_id, plain.id());
+        assert_eq!(svc_type, SVC_TYPE_TCP);
+        assert_eq!(ip_version, 4);
+        assert_eq!(port, tcp_addr.port());
+
+        // a service that only exists from v2 onwards must degrade to
+        // something a v1 peer can still safely skip, rather than handing
+        // it IP_VERSION_HOST or an svc_type it has never heard of
+        let mut buf = BytesMut::new();
+
+        onion.encode_versioned(SVC_TABLE_VERSION_1, &mut buf);
+
+        let (svc_id, svc_type, ip_version, port) = parse_header(&buf[..HEADER_LEN]);
+
+        assert_eq!(svc_id, onion.id());
+        assert_eq!(svc_type, SVC_TYPE_UNSUPPORTED_RTSP);
+        assert_ne!(ip_version, IP_VERSION_HOST);
+        assert_eq!(port, 0);
+    }
+
+    #[test]
+    fn diff_is_incomplete_once_a_generation_falls_out_of_retention() {
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let tcp_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let mut table = SimpleServiceTable::from(Vec::new());
+
+        // generation 1: the service this test tracks across the eviction
+        // boundary
+        let tracked = Service::tcp(1, mac, tcp_addr);
+
+        table.update_services(vec![tracked.clone()]);
+
+        assert_eq!(table.generation(), 1);
+        assert_eq!(table.oldest_generation(), 0);
+
+        // drive the table through more than MAX_RETAINED_GENERATIONS more
+        // changes, so generation 1 (the tracked service's upsert) falls out
+        // of the retained history
+        for i in 0..(MAX_RETAINED_GENERATIONS + 1) {
+            let other = Service::tcp(2, mac, format!("127.0.0.1:{}", 8081 + i).parse().unwrap());
+
+            table.update_services(vec![tracked.clone(), other]);
+        }
+
+        let generation = table.generation();
+
+        assert_eq!(generation, 1 + MAX_RETAINED_GENERATIONS + 1);
+
+        // the tracked service's own upsert (generation 1) has now been
+        // evicted, so the oldest generation still reconstructable via diff
+        // is newer than it
+        assert!(table.oldest_generation() > 1);
+
+        // a diff requested from before the retention window can no longer
+        // report every change that happened (the generation 1 upsert is
+        // gone), but it must still be internally consistent: it should
+        // never claim the tracked service was removed, and its reported
+        // generation must be the table's current one
+        let delta = table.diff(0);
+
+        assert_eq!(delta.generation(), generation);
+        assert!(!delta.removed_ids().contains(&tracked.id()));
+
+        // a diff requested from exactly the oldest retained generation
+        // still reports every change since then, in particular the most
+        // recent upsert of the "other" service
+        let delta = table.diff(table.oldest_generation());
+
+        assert!(delta.upserts().iter().any(|svc| svc.id() == 2));
     }
 }